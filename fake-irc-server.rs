@@ -22,14 +22,21 @@
 
 //! Fake IRC server for testing a plugin on WeeChat
 
-use std::io::{Write, BufRead};
+use std::io::{Read, Write, BufRead};
 use std::io::BufReader;
 use std::io;
+use std::fmt;
 use std::net::{TcpStream, TcpListener};
 use std::iter::Peekable;
 use std::str::CharIndices;
 use std::thread;
 use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fs;
+#[cfg(feature = "tls-native")]
+use std::fs::File;
 
 
 macro_rules! try_expect {
@@ -63,6 +70,142 @@ macro_rules! send_message {
 
 const SERVER: &str = "127.0.0.1";
 const PROGRAMVER: &str = "fake-irc-server-v0.1.0";
+const SUPPORTED_CAPS: &[&str] = &["server-time", "message-tags", "account-tag", "multi-prefix"];
+
+/// Read timeout for each poll attempt; keepalive deadlines are tracked separately.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+
+/// Shared state for simulating channels across connections.
+#[derive(Default)]
+struct Channels {
+    /// channel name -> member nicks
+    members: HashMap<String, HashSet<String>>,
+    /// nick -> a handle to that client's connection, used to relay channel traffic
+    clients: HashMap<String, ConnHandle>,
+    /// nick -> the IRCv3 caps that client ACKed, so relays know which message-tags it expects
+    client_caps: HashMap<String, Vec<String>>,
+}
+
+type SharedChannels = Arc<Mutex<Channels>>;
+
+
+/// Either a bare TCP socket or, with `tls-native`, a TLS session over one.
+enum RawConn {
+    Plain(TcpStream),
+    #[cfg(feature = "tls-native")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+impl RawConn {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self {
+            RawConn::Plain(stream) => stream.set_read_timeout(dur),
+            #[cfg(feature = "tls-native")]
+            RawConn::Tls(stream) => stream.get_ref().set_read_timeout(dur),
+        }
+    }
+}
+
+impl Read for RawConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RawConn::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls-native")]
+            RawConn::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for RawConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RawConn::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls-native")]
+            RawConn::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RawConn::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls-native")]
+            RawConn::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+
+/// A cheap, cloneable handle to a connection's transport, shared between its own
+/// reading thread and other threads relaying channel traffic to it.
+#[derive(Clone)]
+struct ConnHandle(Arc<Mutex<RawConn>>);
+
+impl ConnHandle {
+    fn new(conn: RawConn) -> ConnHandle {
+        ConnHandle(Arc::new(Mutex::new(conn)))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().set_read_timeout(dur)
+    }
+}
+
+impl Read for ConnHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for ConnHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+
+/// Which transport newly-accepted connections should be wrapped in.
+#[derive(Clone)]
+enum TlsMode {
+    Disabled,
+    #[cfg(feature = "tls-native")]
+    Enabled(Arc<rustls::ServerConfig>),
+}
+
+
+/// Build a `rustls::ServerConfig` from a PEM cert chain and key file.
+#[cfg(feature = "tls-native")]
+fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<rustls::ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(File::open(key_path)?))?;
+    let key = match keys.pop() {
+        Some(key) => rustls::PrivateKey(key),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "no PKCS#8 private key found")),
+    };
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)
+}
+
+
+/// Wrap an accepted socket in a rustls server session, producing a `RawConn::Tls`.
+/// The handshake itself runs on the first read or write of the returned connection.
+#[cfg(feature = "tls-native")]
+fn accept_tls(stream: TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<RawConn> {
+    let session = rustls::ServerConnection::new(config)
+        .map_err(io::Error::other)?;
+    Ok(RawConn::Tls(Box::new(rustls::StreamOwned::new(session, stream))))
+}
 
 
 fn main() {
@@ -70,9 +213,54 @@ fn main() {
     // ignore program name
     args.next();
     let port = match args.next() {
-        Some(s) => try_expect!(s.parse(), "PORT argument is not a number. Usage: fake-irc-server [PORT]"),
+        Some(s) => try_expect!(s.parse(), "PORT argument is not a number. Usage: fake-irc-server [PORT] [PING_INTERVAL] [PING_GRACE] [--tls --tls-cert CERT --tls-key KEY]"),
         None => 1234, // default port
     };
+    let ping_interval = match args.next() {
+        Some(s) => try_expect!(s.parse(), "PING_INTERVAL argument is not a number. Usage: fake-irc-server [PORT] [PING_INTERVAL] [PING_GRACE] [--tls --tls-cert CERT --tls-key KEY]"),
+        None => 120, // default: probe after 2 minutes of silence
+    };
+    let ping_grace = match args.next() {
+        Some(s) => try_expect!(s.parse(), "PING_GRACE argument is not a number. Usage: fake-irc-server [PORT] [PING_INTERVAL] [PING_GRACE] [--tls --tls-cert CERT --tls-key KEY]"),
+        None => 30, // default: 30 seconds to answer the PING before we disconnect
+    };
+
+    let mut tls_requested = false;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut script_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls" => tls_requested = true,
+            "--tls-cert" => tls_cert = args.next(),
+            "--tls-key" => tls_key = args.next(),
+            "--script" => script_path = args.next(),
+            other => eprintln!("=== Ignoring unrecognized argument: {}", other),
+        }
+    }
+
+    let tls_mode = if tls_requested {
+        #[cfg(feature = "tls-native")]
+        {
+            let (cert, key) = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => (cert, key),
+                _ => {
+                    eprintln!("--tls requires both --tls-cert PATH and --tls-key PATH");
+                    return;
+                },
+            };
+            let config = try_expect!(load_tls_config(&cert, &key), "Can't load TLS certificate/key");
+            TlsMode::Enabled(Arc::new(config))
+        }
+        #[cfg(not(feature = "tls-native"))]
+        {
+            let _ = (tls_cert, tls_key); // only consumed by the tls-native build
+            eprintln!("This build was compiled without TLS support; rebuild with --features tls-native to use --tls");
+            return;
+        }
+    } else {
+        TlsMode::Disabled
+    };
 
     let serverport = format!("{server}:{port}", server=SERVER, port=port);
     debug!("=== Listening on {}", serverport);
@@ -82,26 +270,62 @@ fn main() {
     );
 
     let (sender, receiver) = mpsc::channel();
+    let (event_sender, event_receiver) = mpsc::channel();
+    let channels: SharedChannels = Arc::new(Mutex::new(Channels::default()));
 
-    thread::spawn(move || process_stdin(receiver));
+    match script_path {
+        Some(script_path) => {
+            let steps = try_expect!(parse_scenario(&script_path), "Can't read scenario script");
+            thread::spawn(move || run_scenario(steps, receiver, event_receiver));
+        },
+        None => {
+            // no scenario running, so just discard the client events nobody is watching
+            thread::spawn(move || for _ in event_receiver.iter() {});
+            thread::spawn(move || process_stdin(receiver));
+        },
+    }
 
     // This will loop forever
     for stream in listener.incoming() {
         let sender = sender.clone();
+        let event_sender = event_sender.clone();
+        let channels = channels.clone();
+        let tls_mode = tls_mode.clone();
         let stream = try_expect!(stream, "Error on incoming stream");
-        thread::spawn(move || process_stream(stream, port, sender));
+        thread::spawn(move || {
+            let conn = match tls_mode {
+                TlsMode::Disabled => RawConn::Plain(stream),
+                #[cfg(feature = "tls-native")]
+                TlsMode::Enabled(config) => match accept_tls(stream, config) {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        eprintln!("=== TLS session setup failed: {}", e);
+                        return;
+                    },
+                },
+            };
+            process_stream(ConnHandle::new(conn), port, sender, event_sender, channels, ping_interval, ping_grace);
+        });
     }
 }
 
 
 /// Process any incoming IRC connection. Automatically reply any PING message with a PONG.
 /// Process registratioin handshake by sending the 001 002 003 004 and 005 numeric reply
-/// to the client.
-fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
+/// to the client. Once registered, also simulates channel membership (JOIN/PART/QUIT/PRIVMSG).
+fn process_stream(
+    conn: ConnHandle,
+    port: usize,
+    sender: Sender<ConnHandle>,
+    event_sender: Sender<ClientEvent>,
+    channels: SharedChannels,
+    ping_interval: u64,
+    ping_grace: u64,
+) {
     debug!("=== Getting new incoming connection");
 
     let mut buff = String::new();
-    let mut reader = BufReader::new(stream);
+    let mut reader = BufReader::new(conn);
 
     // Processing 1: NICK <nick>
     // Processing 2: USER <user> 0 * :<real>
@@ -111,45 +335,109 @@ fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
     let mut real = None;
 
     let mut registration_finished = false;
+    let mut cap_negotiating = false;
+    let mut acked_caps = Vec::new();
+
+    // Keepalive: if the client goes quiet for `ping_interval` seconds we probe it with a
+    // PING; if it doesn't answer within a further `ping_grace` seconds we give up on it.
+    // The read timeout itself is a short, fixed poll tick (`READ_POLL_INTERVAL`) so a
+    // blocking read never holds the `ConnHandle` lock long enough to stall other threads'
+    // writes to this connection; the actual keepalive window is tracked as a deadline.
+    let mut awaiting_pong = false;
+    let mut deadline = Instant::now() + Duration::from_secs(ping_interval);
+    try_expect!(
+        reader.get_ref().set_read_timeout(Some(READ_POLL_INTERVAL)),
+        "Can't set read timeout on TcpStream"
+    );
 
     loop {
-        buff.clear();
+        // note: buff isn't cleared here -- a read can time out mid-line (see the
+        // WouldBlock/TimedOut arm below), and the partial bytes already read must
+        // survive into the next read_line() call instead of being discarded
         match reader.read_line(&mut buff) {
-            Ok(0) => return, // EOF
+            Ok(0) => {
+                quit_all_channels(&channels, &nick, &user, "Connection closed");
+                return; // EOF
+            },
             Ok(_) => {
                 // remove \r\n from the buff
                 match buff.pop() {
                     Some('\n') => (),
-                    _ => continue, // last character isn't \n
+                    _ => { buff.clear(); continue }, // last character isn't \n
                 }
                 match buff.pop() {
                     Some('\r') => (),
-                    _ => continue, // second to last character isn't \r
+                    _ => { buff.clear(); continue }, // second to last character isn't \r
                 }
             },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                if Instant::now() < deadline {
+                    continue; // still within the current keepalive window, poll again
+                }
+
+                if awaiting_pong {
+                    debug!("=== Ping timeout, closing connection");
+                    let _ = write!(reader.get_mut(), "ERROR :Ping timeout\r\n");
+                    quit_all_channels(&channels, &nick, &user, "Ping timeout");
+                    return;
+                }
+
+                awaiting_pong = true;
+                try_expect!(
+                    write!(reader.get_mut(), ":localhost PING :localhost\r\n"),
+                    "Can't write to the TcpStream"
+                );
+                deadline = Instant::now() + Duration::from_secs(ping_grace);
+                continue;
+            },
             Err(_) => {
                 eprintln!("Error reading from TcpStream");
+                quit_all_channels(&channels, &nick, &user, "Connection closed");
                 return;
             },
         }
 
         let message = match IrcMessage::new(&buff) {
             Ok(m) => m,
-            Err(_) => continue, // ignore message error; for now
+            Err(_) => { buff.clear(); continue }, // ignore message error; for now
         };
+        buff.clear(); // full line consumed into `message`, ready for the next one
+
+        // only a genuine PONG answers the keepalive probe; any other traffic
+        // while we're awaiting one must not mask a client that never replies
+        if message.command == Command::Pong {
+            awaiting_pong = false;
+        }
+        if !awaiting_pong {
+            deadline = Instant::now() + Duration::from_secs(ping_interval);
+        }
+
+        // let a scenario script's WAIT/EXPECT directives observe this message
+        let _ = event_sender.send(ClientEvent {
+            command: message.command.clone(),
+            params: message.params.clone(),
+        });
 
-        match &message {
-            IrcMessage { command, params, .. } if upcase_eq(&command, "NICK") => {
-                nick = params.get(0).cloned();
+        match &message.command {
+            Command::Nick => {
+                let new_nick = message.params.first().cloned();
+                if registration_finished {
+                    if let (Some(old_nick), Some(new_nick), Some(u)) = (&nick, &new_nick, &user) {
+                        if old_nick != new_nick {
+                            rename_client(&channels, old_nick, new_nick, u);
+                        }
+                    }
+                }
+                nick = new_nick;
                 debug!("=== Message: {:?}", message);
             },
-            IrcMessage { command, params, .. } if upcase_eq(&command, "USER") => {
-                user = params.get(0).cloned();
-                real = params.get(3).cloned();
+            Command::User => {
+                user = message.params.first().cloned();
+                real = message.params.get(3).cloned();
                 debug!("=== Message: {:?}", message);
             },
-            IrcMessage { command, params, .. } if upcase_eq(&command, "PING") => {
-                let param = params.get(0).cloned().unwrap_or_default();
+            Command::Ping => {
+                let param = message.params.first().cloned().unwrap_or_default();
 
                 // Not using the send_message! macro since we don't want it to be
                 // logged. The CRLF is important, don't forget it.
@@ -158,7 +446,31 @@ fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
                     "Can't write to the TcpStream"
                 );
             },
-            message => debug!("=== Message: {:?}", message),
+            Command::Join if registration_finished => {
+                if let (Some(n), Some(u), Some(channel)) = (&nick, &user, message.params.first()) {
+                    handle_join(reader.get_mut(), &channels, n, u, channel);
+                }
+            },
+            Command::Part if registration_finished => {
+                if let (Some(n), Some(u), Some(channel)) = (&nick, &user, message.params.first()) {
+                    handle_part(&channels, n, u, channel);
+                }
+            },
+            Command::Quit if registration_finished => {
+                let reason = message.params.first().cloned().unwrap_or_else(|| "Quit".to_string());
+                quit_all_channels(&channels, &nick, &user, &reason);
+            },
+            Command::PrivMsg if registration_finished => {
+                if let (Some(n), Some(u), Some(channel), Some(text)) =
+                    (&nick, &user, message.params.first(), message.params.get(1))
+                {
+                    handle_privmsg(&channels, n, u, channel, text);
+                }
+            },
+            Command::Cap => {
+                handle_cap(reader.get_mut(), &message.params, &mut cap_negotiating, &mut acked_caps);
+            },
+            _ => debug!("=== Message: {:?}", message),
         }
 
         if registration_finished {
@@ -166,9 +478,9 @@ fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
         }
 
 
-        // handle registration
+        // handle registration; deferred until CAP negotiation (if any was started) has ended
         match (&nick, &user, &real) {
-            (Some(ref nick), Some(ref user), Some(_)) => {
+            (Some(ref nick), Some(ref user), Some(_)) if !cap_negotiating => {
                 // we have a complete registration from user
                 // Send 001, 002, 003, 004, and 005
                 send_message!(reader.get_mut(),
@@ -201,10 +513,13 @@ fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
                               nick=nick
                 );
 
-                // send this stream to the process_input() after the handshake finished
-                if let Ok(stream) = reader.get_ref().try_clone() {
-                    let _ = sender.send(stream); // ignore sending error
-                }
+                // send a handle to this connection to process_stdin() or run_scenario() after the handshake finished
+                let _ = sender.send(reader.get_ref().clone()); // ignore sending error
+
+                // register this client so other connections can route channel traffic to it
+                let mut state = channels.lock().unwrap();
+                state.clients.insert(nick.clone(), reader.get_ref().clone());
+                state.client_caps.insert(nick.clone(), acked_caps.clone());
 
                 // we are done here, don't send this message again
                 registration_finished = true;
@@ -214,16 +529,213 @@ fn process_stream(stream: TcpStream, port: usize, sender: Sender<TcpStream>) {
     }
 
 
-    // helper functions
+}
+
+
+/// Rekey a renamed client's routing entry and channel memberships, then broadcast the NICK.
+fn rename_client(channels: &SharedChannels, old_nick: &str, new_nick: &str, user: &str) {
+    let mut state = channels.lock().unwrap();
+    if let Some(handle) = state.clients.remove(old_nick) {
+        state.clients.insert(new_nick.to_string(), handle);
+    }
+    if let Some(caps) = state.client_caps.remove(old_nick) {
+        state.client_caps.insert(new_nick.to_string(), caps);
+    }
+
+    // broadcast the rename to the client itself and every channel co-member
+    let mut targets = HashSet::new();
+    targets.insert(new_nick.to_string());
+    for members in state.members.values_mut() {
+        if members.remove(old_nick) {
+            members.insert(new_nick.to_string());
+            targets.extend(members.iter().cloned());
+        }
+    }
+
+    let line = format!(":{}!{}@localhost NICK :{}\r\n", old_nick, user, new_nick);
+    broadcast(&mut state.clients, &targets, &line);
+}
+
+
+/// Handle `JOIN <channel>`: add the nick to the channel and reply with the usual JOIN/NAMES burst.
+fn handle_join(writer: &mut ConnHandle, channels: &SharedChannels, nick: &str, user: &str, channel: &str) {
+    let names = {
+        let mut state = channels.lock().unwrap();
+        let members = state.members.entry(channel.to_string()).or_default();
+        members.insert(nick.to_string());
+        members.clone()
+    };
+
+    send_message!(writer, ":{nick}!{user}@localhost JOIN {channel}", nick=nick, user=user, channel=channel);
+    send_message!(writer, ":localhost 331 {nick} {channel} :No topic is set", nick=nick, channel=channel);
+
+    let mut names_list: Vec<&str> = names.iter().map(String::as_str).collect();
+    names_list.sort();
+    send_message!(writer, ":localhost 353 {nick} = {channel} :{names}",
+                  nick=nick, channel=channel, names=names_list.join(" "));
+    send_message!(writer, ":localhost 366 {nick} {channel} :End of /NAMES list.", nick=nick, channel=channel);
+}
+
+
+/// Handle `PART <channel>`: remove the nick from the channel and broadcast the PART.
+fn handle_part(channels: &SharedChannels, nick: &str, user: &str, channel: &str) {
+    let mut state = channels.lock().unwrap();
+    if let Some(members) = state.members.get_mut(channel) {
+        // the parting client must see its own PART too, so snapshot targets before removing it
+        let targets = members.clone();
+        members.remove(nick);
+        let line = format!(":{}!{}@localhost PART {}\r\n", nick, user, channel);
+        broadcast(&mut state.clients, &targets, &line);
+    }
+}
+
+
+/// Handle `PRIVMSG <channel> :<text>`: relay the message to every other member of the channel.
+fn handle_privmsg(channels: &SharedChannels, nick: &str, user: &str, channel: &str, text: &str) {
+    if !channel.starts_with('#') {
+        return; // only channel messages are simulated, not direct client-to-client PRIVMSG
+    }
+
+    let mut state = channels.lock().unwrap();
+    if let Some(members) = state.members.get(channel) {
+        let targets: Vec<String> = members.iter().filter(|&m| m != nick).cloned().collect();
+        let body = format!(":{}!{}@localhost PRIVMSG {} :{}\r\n", nick, user, channel, text);
+        for target in targets {
+            let tags = tag_prefix(state.client_caps.get(&target), user);
+            if let Some(stream) = state.clients.get_mut(&target) {
+                let _ = write!(stream, "{}{}", tags, body);
+            }
+        }
+    }
+}
+
+
+/// Build the `@key=value;...` prefix for a relayed message, based on the recipient's ACKed caps.
+fn tag_prefix(acked_caps: Option<&Vec<String>>, account: &str) -> String {
+    let acked_caps = match acked_caps {
+        Some(caps) if caps.iter().any(|c| c == "message-tags") => caps,
+        _ => return String::new(),
+    };
 
-    fn upcase_eq(left: &str, right: &str) -> bool {
-        &left.to_ascii_uppercase() == right
+    let mut tags = Vec::new();
+    if acked_caps.iter().any(|c| c == "server-time") {
+        tags.push(format!("time={}", format_server_time(SystemTime::now())));
+    }
+    if acked_caps.iter().any(|c| c == "account-tag") {
+        tags.push(format!("account={}", account));
+    }
+
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!("@{} ", tags.join(";"))
+    }
+}
+
+
+/// Render a timestamp as an IRCv3 `server-time` value: `YYYY-MM-DDThh:mm:ss.sssZ`.
+fn format_server_time(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let millis = since_epoch.subsec_millis();
+
+    let days = (secs / 86400) as i64;
+    let time_of_day = secs % 86400;
+    let (hour, minute, second) = (time_of_day / 3600, (time_of_day / 60) % 60, time_of_day % 60);
+
+    // Howard Hinnant's civil_from_days: days-since-epoch -> proleptic Gregorian y/m/d
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let year = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let day = doy - (153 * mp + 2) / 5 + 1; // [1, 31]
+    let month = if mp < 10 { mp + 3 } else { mp - 9 }; // [1, 12]
+    let year = if month <= 2 { year + 1 } else { year };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hour, minute, second, millis)
+}
+
+
+/// Remove a disconnecting client from every channel it was in, broadcasting a QUIT to each.
+fn quit_all_channels(channels: &SharedChannels, nick: &Option<String>, user: &Option<String>, reason: &str) {
+    let nick = match nick {
+        Some(nick) => nick.clone(),
+        None => return, // never registered, nothing to clean up
+    };
+    let user = user.clone().unwrap_or_default();
+
+    let mut state = channels.lock().unwrap();
+    state.clients.remove(&nick);
+    state.client_caps.remove(&nick);
+
+    let joined_channels: Vec<String> = state.members.iter()
+        .filter(|(_, members)| members.contains(&nick))
+        .map(|(channel, _)| channel.clone())
+        .collect();
+
+    let line = format!(":{}!{}@localhost QUIT :{}\r\n", nick, user, reason);
+    for channel in joined_channels {
+        if let Some(members) = state.members.get_mut(&channel) {
+            members.remove(&nick);
+            let targets = members.clone();
+            broadcast(&mut state.clients, &targets, &line);
+        }
+    }
+}
+
+
+/// Handle IRCv3 `CAP` negotiation (`LS`, `REQ`, `LIST`, `END`).
+fn handle_cap(writer: &mut ConnHandle, params: &[String], cap_negotiating: &mut bool, acked_caps: &mut Vec<String>) {
+    let subcommand = match params.first() {
+        Some(subcommand) => subcommand.to_ascii_uppercase(),
+        None => return,
+    };
+
+    match subcommand.as_str() {
+        "LS" => {
+            *cap_negotiating = true;
+            send_message!(writer, ":localhost CAP * LS :{caps}", caps=SUPPORTED_CAPS.join(" "));
+        },
+        "REQ" => {
+            *cap_negotiating = true;
+            let requested = params.get(1).map(String::as_str).unwrap_or("");
+            let (ack, nak): (Vec<&str>, Vec<&str>) = requested.split_whitespace()
+                .partition(|cap| SUPPORTED_CAPS.contains(cap));
+
+            if !ack.is_empty() {
+                send_message!(writer, ":localhost CAP * ACK :{caps}", caps=ack.join(" "));
+                acked_caps.extend(ack.into_iter().map(str::to_string));
+            }
+            if !nak.is_empty() {
+                send_message!(writer, ":localhost CAP * NAK :{caps}", caps=nak.join(" "));
+            }
+        },
+        "LIST" => {
+            send_message!(writer, ":localhost CAP * LIST :{caps}", caps=acked_caps.join(" "));
+        },
+        "END" => {
+            *cap_negotiating = false;
+        },
+        _ => debug!("=== Unhandled CAP subcommand: {}", subcommand),
+    }
+}
+
+
+/// Write a pre-formatted line to every nick in `targets` that has a registered client stream.
+fn broadcast(clients: &mut HashMap<String, ConnHandle>, targets: &HashSet<String>, line: &str) {
+    for nick in targets {
+        if let Some(stream) = clients.get_mut(nick) {
+            let _ = write!(stream, "{}", line);
+        }
     }
 }
 
 
 /// Handle input from the user. Send it to all connected client.
-fn process_stdin(receiver: Receiver<TcpStream>) {
+fn process_stdin(receiver: Receiver<ConnHandle>) {
     let mut streams = Vec::new();
     let stdin = io::stdin();
     for line in stdin.lock().lines() {
@@ -254,6 +766,136 @@ fn process_stdin(receiver: Receiver<TcpStream>) {
 }
 
 
+//==== Scripted scenarios ====
+// Drives server responses from a script file instead of raw stdin echo, so a scenario
+// can be asserted against deterministically (e.g. from a CI job).
+
+
+/// A client-sent message, forwarded to the scenario runner for `WAIT`/`EXPECT`.
+#[derive(Debug)]
+struct ClientEvent {
+    command: Command,
+    params: Vec<String>,
+}
+
+
+/// A single directive parsed out of a scenario script.
+#[derive(Debug, PartialEq, Eq)]
+enum ScenarioStep {
+    /// `SEND <line>`: emit a raw line (sans "\r\n") to every connected client.
+    Send(String),
+    /// `WAIT <command>`: block until a client sends a message with a matching `Command`.
+    Wait(Command),
+    /// `SLEEP <ms>`: pause the scenario for the given number of milliseconds.
+    Sleep(u64),
+    /// `EXPECT <command> <param-substring>`: the next client message must match `Command`
+    /// and have a param containing the given substring, or the process exits nonzero.
+    Expect(Command, String),
+}
+
+
+/// Parse a scenario script: one directive per line, blank lines and `#`-comments ignored.
+fn parse_scenario(path: &str) -> io::Result<Vec<ScenarioStep>> {
+    let contents = fs::read_to_string(path)?;
+    let mut steps = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = match line.split_once(' ') {
+            Some((keyword, rest)) => (keyword, rest.trim()),
+            None => (line, ""),
+        };
+
+        let step = match keyword.to_ascii_uppercase().as_str() {
+            "SEND" => ScenarioStep::Send(rest.to_string()),
+            "WAIT" => ScenarioStep::Wait(Command::from(rest)),
+            "SLEEP" => match rest.parse() {
+                Ok(ms) => ScenarioStep::Sleep(ms),
+                Err(_) => {
+                    eprintln!("=== Ignoring malformed scenario line: {}", line);
+                    continue;
+                },
+            },
+            "EXPECT" => match rest.split_once(' ') {
+                Some((command, substring)) => ScenarioStep::Expect(Command::from(command), substring.to_string()),
+                None => ScenarioStep::Expect(Command::from(rest), String::new()),
+            },
+            _ => {
+                eprintln!("=== Ignoring unknown scenario directive: {}", line);
+                continue;
+            },
+        };
+
+        steps.push(step);
+    }
+
+    Ok(steps)
+}
+
+
+/// Run a parsed scenario, exiting nonzero on an `EXPECT` mismatch.
+fn run_scenario(steps: Vec<ScenarioStep>, receiver: Receiver<ConnHandle>, events: Receiver<ClientEvent>) {
+    let mut streams = Vec::new();
+
+    for step in steps {
+        // pick up any client that registered since the last directive
+        loop {
+            match receiver.try_recv() {
+                Ok(s) => streams.push(s),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        match step {
+            ScenarioStep::Send(line) => {
+                debug!("=== Scenario SEND: {}", line);
+                for s in &mut streams {
+                    send_message!(s, "{}", line);
+                }
+            },
+            ScenarioStep::Sleep(ms) => {
+                debug!("=== Scenario SLEEP: {}ms", ms);
+                thread::sleep(Duration::from_millis(ms));
+            },
+            ScenarioStep::Wait(command) => {
+                debug!("=== Scenario WAIT: {}", command);
+                loop {
+                    match events.recv() {
+                        Ok(event) if event.command == command => break,
+                        Ok(_) => continue,
+                        Err(_) => {
+                            eprintln!("=== Scenario failed: no more clients to WAIT on for {}", command);
+                            std::process::exit(1);
+                        },
+                    }
+                }
+            },
+            ScenarioStep::Expect(command, substring) => {
+                debug!("=== Scenario EXPECT: {} containing {:?}", command, substring);
+                match events.recv() {
+                    Ok(event) if event.command == command && event.params.iter().any(|p| p.contains(&substring)) => (),
+                    Ok(event) => {
+                        eprintln!("=== Scenario failed: expected {} containing {:?}, got {:?}", command, substring, event);
+                        std::process::exit(1);
+                    },
+                    Err(_) => {
+                        eprintln!("=== Scenario failed: no more clients to EXPECT from for {}", command);
+                        std::process::exit(1);
+                    },
+                }
+            },
+        }
+    }
+
+    debug!("=== Scenario finished");
+}
+
+
 //==== Parsing IRC Message ====
 // A rather simple parser for IRC protocol.
 
@@ -261,10 +903,11 @@ fn process_stdin(receiver: Receiver<TcpStream>) {
 /// Simple struct that represents the parsed IRC message.
 /// For simplicity sake, we use owned string. It should be possible in theory to use a &str instead.
 #[derive(Debug)]
+#[allow(dead_code)] // tag/prefix are parsed eagerly; no handler consumes them outside tests yet
 struct IrcMessage {
-    tag: Option<String>,
+    tag: Option<Vec<(String, String)>>,
     prefix: Option<String>,
-    command: String,
+    command: Command,
     params: Vec<String>,
 }
 
@@ -282,10 +925,10 @@ impl IrcMessage {
         use IrcError::*;
 
         let mut parser = IrcParser::new(input);
-        let tag = parser.parse_word_if_start_with('@');
+        let tag = parser.parse_tags();
         let prefix = parser.parse_word_if_start_with(':');
         let command = match parser.parse_word() {
-            Some(command) => command,
+            Some(command) => Command::from(command.as_str()),
             None => return Err(NoCommand),
         };
         let params = parser.parse_params();
@@ -295,6 +938,99 @@ impl IrcMessage {
 }
 
 
+/// Unescape an IRCv3 message-tag value: `\:` -> `;`, `\s` -> space, `\\` -> `\`, `\r` -> CR, `\n` -> LF.
+fn unescape_tag_value(raw: &str) -> String {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('\\') => unescaped.push('\\'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => (), // trailing lone backslash: drop it
+        }
+    }
+
+    unescaped
+}
+
+
+/// A typed IRC command, so handlers match on variants instead of raw strings.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Command {
+    Nick,
+    User,
+    Pass,
+    Quit,
+    Join,
+    Part,
+    PrivMsg,
+    Notice,
+    Ping,
+    Pong,
+    Cap,
+    Numeric(u16),
+    Unknown(String),
+}
+
+impl From<&str> for Command {
+    /// Map the wire token (case-insensitively) to its `Command` variant.
+    /// Tokens that parse as a `u16` become `Numeric`; anything else becomes `Unknown`.
+    fn from(token: &str) -> Self {
+        use Command::*;
+
+        match token.to_ascii_uppercase().as_str() {
+            "NICK" => Nick,
+            "USER" => User,
+            "PASS" => Pass,
+            "QUIT" => Quit,
+            "JOIN" => Join,
+            "PART" => Part,
+            "PRIVMSG" => PrivMsg,
+            "NOTICE" => Notice,
+            "PING" => Ping,
+            "PONG" => Pong,
+            "CAP" => Cap,
+            upper => match upper.parse() {
+                Ok(n) => Numeric(n),
+                Err(_) => Unknown(upper.to_string()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    /// Render the command back into its canonical wire form.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Command::*;
+
+        match self {
+            Nick => write!(f, "NICK"),
+            User => write!(f, "USER"),
+            Pass => write!(f, "PASS"),
+            Quit => write!(f, "QUIT"),
+            Join => write!(f, "JOIN"),
+            Part => write!(f, "PART"),
+            PrivMsg => write!(f, "PRIVMSG"),
+            Notice => write!(f, "NOTICE"),
+            Ping => write!(f, "PING"),
+            Pong => write!(f, "PONG"),
+            Cap => write!(f, "CAP"),
+            Numeric(n) => write!(f, "{:03}", n),
+            Unknown(token) => write!(f, "{}", token),
+        }
+    }
+}
+
+
 /// A rather simple parser. We save the input and a marker to the portion of the input that hasn't been read.
 struct IrcParser<'a> {
     iter: Peekable<CharIndices<'a>>,
@@ -310,7 +1046,7 @@ impl<'a> IrcParser<'a> {
     fn new(input: &'a str) -> IrcParser<'a> {
         IrcParser {
             iter: input.char_indices().peekable(),
-            input: input,
+            input,
             marker: 0,
         }
     }
@@ -367,6 +1103,32 @@ impl<'a> IrcParser<'a> {
         }
     }
 
+    /// Parse the leading `@key1=value1;key2=value2` tag blob into a key/value list, if present.
+    fn parse_tags(&mut self) -> Option<Vec<(String, String)>> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('@') => {
+                self.consume_char(); // don't include the leading '@'
+                let start = self.marker;
+                self.skip_word();
+                let end = self.marker;
+
+                let tags = self.input[start..end]
+                    .split(';')
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| {
+                        let mut parts = entry.splitn(2, '=');
+                        let key = parts.next().unwrap_or("").to_string();
+                        let value = unescape_tag_value(parts.next().unwrap_or(""));
+                        (key, value)
+                    })
+                    .collect();
+                Some(tags)
+            },
+            _ => None,
+        }
+    }
+
     /// Parse a single word and return them.
     fn parse_word(&mut self) -> Option<String> {
         self.skip_whitespace();
@@ -402,3 +1164,168 @@ impl<'a> IrcParser<'a> {
         params
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tags_splits_keys_and_unescapes_values() {
+        let message = IrcMessage::new("@time=2019-02-28T12:00:00.000Z;account=jilles PRIVMSG #chan :hi").unwrap();
+        assert_eq!(
+            message.tag,
+            Some(vec![
+                ("time".to_string(), "2019-02-28T12:00:00.000Z".to_string()),
+                ("account".to_string(), "jilles".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_tags_is_none_without_a_leading_at_segment() {
+        let message = IrcMessage::new("PRIVMSG #chan :hi").unwrap();
+        assert_eq!(message.tag, None);
+    }
+
+    #[test]
+    fn parse_tags_keeps_plus_prefix_and_vendor_segment_on_the_key() {
+        let message = IrcMessage::new("@+example.com/foo=bar PRIVMSG #chan :hi").unwrap();
+        assert_eq!(message.tag, Some(vec![("+example.com/foo".to_string(), "bar".to_string())]));
+    }
+
+    #[test]
+    fn parse_tags_defaults_missing_value_to_empty_string() {
+        let message = IrcMessage::new("@solo PRIVMSG #chan :hi").unwrap();
+        assert_eq!(message.tag, Some(vec![("solo".to_string(), "".to_string())]));
+    }
+
+    #[test]
+    fn unescape_tag_value_handles_the_full_escape_table() {
+        assert_eq!(unescape_tag_value(r"semi\:colon"), "semi;colon");
+        assert_eq!(unescape_tag_value(r"sp\sace"), "sp ace");
+        assert_eq!(unescape_tag_value(r"back\\slash"), r"back\slash");
+        assert_eq!(unescape_tag_value(r"cr\riage"), "cr\riage");
+        assert_eq!(unescape_tag_value(r"new\nline"), "new\nline");
+        assert_eq!(unescape_tag_value(r"trailing\"), "trailing");
+        assert_eq!(unescape_tag_value(r"unknown\xescape"), "unknownxescape");
+    }
+
+    #[test]
+    fn format_server_time_renders_the_epoch() {
+        assert_eq!(format_server_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn format_server_time_renders_a_known_date() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_551_355_200_500); // 2019-02-28T12:00:00.500Z
+        assert_eq!(format_server_time(t), "2019-02-28T12:00:00.500Z");
+    }
+
+    #[test]
+    fn tag_prefix_is_empty_without_message_tags_cap() {
+        let caps = vec!["server-time".to_string()];
+        assert_eq!(tag_prefix(Some(&caps), "jilles"), "");
+        assert_eq!(tag_prefix(None, "jilles"), "");
+    }
+
+    #[test]
+    fn tag_prefix_includes_only_the_negotiated_tags() {
+        let caps = vec!["message-tags".to_string(), "account-tag".to_string()];
+        assert_eq!(tag_prefix(Some(&caps), "jilles"), "@account=jilles ");
+    }
+
+    #[test]
+    fn command_from_is_case_insensitive() {
+        assert_eq!(Command::from("privmsg"), Command::PrivMsg);
+        assert_eq!(Command::from("PrIvMsG"), Command::PrivMsg);
+    }
+
+    #[test]
+    fn command_from_parses_numerics_and_falls_back_to_unknown() {
+        assert_eq!(Command::from("001"), Command::Numeric(1));
+        assert_eq!(Command::from("FOOBAR"), Command::Unknown("FOOBAR".to_string()));
+    }
+
+    #[test]
+    fn command_display_round_trips_through_from() {
+        for token in ["NICK", "USER", "PASS", "QUIT", "JOIN", "PART", "PRIVMSG", "NOTICE", "PING", "PONG", "CAP"] {
+            assert_eq!(Command::from(token).to_string(), token);
+        }
+        assert_eq!(Command::Numeric(1).to_string(), "001");
+        assert_eq!(Command::Unknown("FOOBAR".to_string()).to_string(), "FOOBAR");
+    }
+
+    /// A loopback `ConnHandle`/peer pair for exercising handlers that write to a real writer.
+    fn conn_pair() -> (ConnHandle, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (ConnHandle::new(RawConn::Plain(server)), peer)
+    }
+
+    fn read_available(stream: &mut TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[test]
+    fn handle_cap_req_splits_supported_and_unsupported_caps_into_ack_and_nak() {
+        let (mut conn, mut peer) = conn_pair();
+        let mut cap_negotiating = false;
+        let mut acked_caps = Vec::new();
+
+        handle_cap(
+            &mut conn,
+            &["REQ".to_string(), "message-tags bogus-cap".to_string()],
+            &mut cap_negotiating,
+            &mut acked_caps,
+        );
+
+        let reply = read_available(&mut peer);
+        assert!(reply.contains("CAP * ACK :message-tags\r\n"), "{}", reply);
+        assert!(reply.contains("CAP * NAK :bogus-cap\r\n"), "{}", reply);
+        assert_eq!(acked_caps, vec!["message-tags".to_string()]);
+    }
+
+    #[test]
+    fn handle_cap_req_acks_nothing_when_every_cap_is_unsupported() {
+        let (mut conn, mut peer) = conn_pair();
+        let mut cap_negotiating = false;
+        let mut acked_caps = Vec::new();
+
+        handle_cap(
+            &mut conn,
+            &["REQ".to_string(), "bogus-cap".to_string()],
+            &mut cap_negotiating,
+            &mut acked_caps,
+        );
+
+        let reply = read_available(&mut peer);
+        assert!(!reply.contains("ACK"), "{}", reply);
+        assert!(reply.contains("CAP * NAK :bogus-cap\r\n"), "{}", reply);
+        assert!(acked_caps.is_empty());
+    }
+
+    fn parse_scenario_str(contents: &str) -> Vec<ScenarioStep> {
+        let path = std::env::temp_dir().join(format!("fake-irc-server-test-{:?}.scenario", thread::current().id()));
+        fs::write(&path, contents).unwrap();
+        let steps = parse_scenario(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        steps
+    }
+
+    #[test]
+    fn parse_scenario_ignores_blank_lines_and_comments() {
+        let steps = parse_scenario_str("\n# a comment\nSLEEP 10\n  \n");
+        assert_eq!(steps, vec![ScenarioStep::Sleep(10)]);
+    }
+
+    #[test]
+    fn parse_scenario_ignores_unknown_directives_and_malformed_sleep() {
+        let steps = parse_scenario_str("BOGUS foo\nSLEEP notanumber\nSEND hi\r\n");
+        assert_eq!(steps, vec![ScenarioStep::Send("hi".to_string())]);
+    }
+}